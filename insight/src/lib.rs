@@ -4,6 +4,11 @@ use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
 use crossbeam::queue::ArrayQueue;
 use bitflags::bitflags;
 
+#[cfg(not(loom))]
+use std::sync::atomic::Ordering;
+#[cfg(loom)]
+use loom::sync::atomic::Ordering;
+
 pub struct AllocImpl<A> {
     inner: A,
 }
@@ -29,20 +34,191 @@ impl Default for AllocLog {
 
 bitflags! {
     struct AllocFlags: u32 {
-        const LOG_DISABLED = 0b00000001;
-        const LOG_ENABLED  = 0b00000010;
-        const FORBID       = 0b00000100;
+        const LOG_DISABLED   = 0b00000001;
+        const LOG_ENABLED    = 0b00000010;
+        const FORBID         = 0b00000100;
+        const FORBID_RECORD  = 0b00001000;
+        const BUDGET         = 0b00010000;
+    }
+}
+
+/// A spinning mutual-exclusion flag for the small critical sections around
+/// `LIVE_ALLOCS`/`STATS_MAP`. Unlike a bare test-and-set that gives up and
+/// drops the caller's work on contention, `lock()` blocks until the previous
+/// holder releases, so a concurrent writer on another thread never loses an
+/// insert/remove. Safe to use from inside the allocator itself: it never
+/// allocates.
+struct SpinLock(AtomicBool);
+
+impl SpinLock {
+    const fn new() -> Self {
+        SpinLock(AtomicBool::new(false))
+    }
+
+    fn lock(&self) -> SpinLockGuard<'_> {
+        while self
+            .0
+            .compare_exchange_weak(false, true, SeqCst, SeqCst)
+            .is_err()
+        {
+            #[cfg(not(loom))]
+            std::hint::spin_loop();
+            #[cfg(loom)]
+            loom::thread::yield_now();
+        }
+        SpinLockGuard(self)
     }
 }
 
+struct SpinLockGuard<'a>(&'a SpinLock);
+
+impl Drop for SpinLockGuard<'_> {
+    fn drop(&mut self) {
+        self.0 .0.store(false, SeqCst);
+    }
+}
+
+/// A `static`-friendly cell for state that's only ever touched while a
+/// `SpinLock` is held. A plain `static mut` works too, but taking `&mut` to
+/// it means taking a mutable reference to a `static`, which current
+/// toolchains flag (`static_mut_refs`) even when a lock genuinely
+/// serializes every access - same problem `alloc_log_state` solves for
+/// `ALLOC_LOG` with an `AtomicPtr` instead of `static mut Option<_>`. This
+/// is the `Cell`-like equivalent for `LIVE_ALLOCS`/`STATS_MAP`, which need
+/// a real `&mut T` handed to their lock-scoped closures rather than an
+/// atomic load/store.
+struct LockedCell<T>(std::cell::UnsafeCell<T>);
+
+unsafe impl<T> Sync for LockedCell<T> {}
+
+impl<T> LockedCell<T> {
+    const fn new(value: T) -> Self {
+        Self(std::cell::UnsafeCell::new(value))
+    }
+
+    /// Safety: the caller must hold the `SpinLock` guarding this cell for
+    /// as long as the returned pointer is dereferenced.
+    #[inline(always)]
+    unsafe fn get(&self) -> *mut T {
+        self.0.get()
+    }
+}
 
 const ALLOC_LOG_SIZE: usize = 4096;
 
-static mut ALLOC_LOG: Option<ArrayQueue<AllocLog>> = None;
-static mut ALLOC_INITIALIZING: AtomicBool = AtomicBool::new(false);
+// `ALLOC_LOG` used to be a `static mut Option<ArrayQueue<_>>` guarded by a
+// plain `is_none() && !initializing` check, which lets a second thread
+// observe `None` while the first thread is still constructing the queue and
+// then unwrap a `None`, or let two threads both pass the check and each
+// allocate their own queue. Instead the queue is heap-allocated once and
+// published through an `AtomicPtr`: the thread that wins the
+// compare-and-swap on `alloc_log_state::INITIALIZING` builds the queue and
+// stores the pointer with `Release`; every other thread spins loading the
+// pointer with `Acquire` until it sees that store, so nobody ever reads a
+// pointer whose pointee isn't fully initialized.
+//
+// Plain `const fn` statics under the normal build (zero-cost, no lazy
+// machinery needed); under `--cfg loom`, `loom::sync::atomic::AtomicPtr::new`
+// and `AtomicBool::new` aren't `const fn` and loom needs fresh state per
+// model iteration, so the same names are backed by `loom::lazy_static!`
+// instead. Either way `alloc_log()` below only ever sees `AtomicPtr`/
+// `AtomicBool` (std's or loom's, matched by `Ordering` from the cfg-gated
+// import above), so the race it runs is the real one under loom.
+#[cfg(not(loom))]
+mod alloc_log_state {
+    use super::AllocLog;
+    use crossbeam::queue::ArrayQueue;
+    use std::sync::atomic::{AtomicBool, AtomicPtr};
+
+    pub static PTR: AtomicPtr<ArrayQueue<AllocLog>> = AtomicPtr::new(std::ptr::null_mut());
+    pub static INITIALIZING: AtomicBool = AtomicBool::new(false);
+}
+
+#[cfg(loom)]
+mod alloc_log_state {
+    use super::AllocLog;
+    use crossbeam::queue::ArrayQueue;
+
+    loom::lazy_static! {
+        pub static ref PTR: loom::sync::atomic::AtomicPtr<ArrayQueue<AllocLog>> =
+            loom::sync::atomic::AtomicPtr::new(std::ptr::null_mut());
+        pub static ref INITIALIZING: loom::sync::atomic::AtomicBool =
+            loom::sync::atomic::AtomicBool::new(false);
+    }
+}
+
+fn alloc_log() -> &'static ArrayQueue<AllocLog> {
+    loop {
+        let ptr = alloc_log_state::PTR.load(Ordering::Acquire);
+        if !ptr.is_null() {
+            return unsafe { &*ptr };
+        }
+
+        if alloc_log_state::INITIALIZING
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            let raw = Box::into_raw(Box::new(ArrayQueue::new(ALLOC_LOG_SIZE)));
+            alloc_log_state::PTR.store(raw, Ordering::Release);
+            return unsafe { &*raw };
+        }
+
+        #[cfg(not(loom))]
+        std::hint::spin_loop();
+        #[cfg(loom)]
+        loom::thread::yield_now();
+    }
+}
 
 thread_local!(static ALLOC_MODE: Cell<AllocFlags> = Cell::new(AllocFlags::LOG_DISABLED));
 
+// Everything `AllocImpl::alloc`/`realloc`/`dealloc` do for their own
+// bookkeeping - building a `Vec` for a captured backtrace, cloning a trace
+// into a `StatsEntry`, even first touching `ALLOC_MODE`/`SAMPLE_COUNTER` -
+// can itself allocate, which re-enters `AllocImpl::alloc` on the same
+// thread. `BOOKKEEPING` marks "this thread is already inside its own
+// bookkeeping"; a nested `alloc` call sees it set and takes the fast path
+// straight to the inner allocator, with no logging, sampling, or forbidding.
+thread_local!(static BOOKKEEPING: Cell<bool> = Cell::new(false));
+
+/// Clears `BOOKKEEPING` for this thread when dropped. The `forbid`/
+/// `allow_up_to` panics raised from inside bookkeeping unwind straight
+/// through the rest of `alloc`/`realloc`/`dealloc`, past any explicit
+/// "clear the flag" call at the end of the function; tying the clear to
+/// `Drop` instead means it still runs during unwinding, so a thread that
+/// catches one of those panics doesn't find itself permanently stuck on
+/// the fast path.
+struct BookkeepingGuard;
+
+impl Drop for BookkeepingGuard {
+    fn drop(&mut self) {
+        let _ = BOOKKEEPING.try_with(|flag| flag.set(false));
+    }
+}
+
+/// Marks entry into the allocator's own bookkeeping for this thread.
+/// Returns `None` - meaning "take the fast path, do no bookkeeping" - if
+/// the thread is already inside bookkeeping (true reentrancy), or if
+/// `BOOKKEEPING` can no longer be accessed (`try_with` returns `Err` once
+/// the thread-local has been destroyed at thread exit, so we degrade
+/// gracefully instead of unwrapping). A call made while `BOOKKEEPING` is
+/// itself first being initialized is not detected.
+/// Otherwise returns a `BookkeepingGuard` that clears the flag on drop.
+#[inline(always)]
+fn enter_bookkeeping() -> Option<BookkeepingGuard> {
+    BOOKKEEPING
+        .try_with(|flag| {
+            if flag.get() {
+                false
+            } else {
+                flag.set(true);
+                true
+            }
+        })
+        .unwrap_or(false)
+        .then(|| BookkeepingGuard)
+}
+
 pub fn no_log<F, R>(f: F) -> R
     where
         F: FnOnce() -> R,
@@ -59,16 +235,81 @@ pub fn forbid<F, R>(f: F) -> R
     f()
 }
 
+/// Enables the `AllocLog` queue `dump_alloc` drains for the duration of
+/// `f`: every sampled allocation inside `f` is pushed onto it as an
+/// `AllocLog::Test` entry (mirroring `no_log`/`forbid`, just flipping
+/// `LOG_ENABLED` on instead of `LOG_DISABLED`).
+pub fn with_logging<F, R>(f: F) -> R
+    where
+        F: FnOnce() -> R,
+{
+    let _guard = Guard::new(AllocFlags::LOG_ENABLED);
+    f()
+}
+
+/// One allocation performed inside a `forbid_recording` scope.
+#[derive(Clone, Debug)]
+pub struct Violation {
+    pub layout: Layout,
+    pub frames: Vec<String>,
+}
+
+thread_local!(static VIOLATIONS: std::cell::RefCell<Vec<Violation>> = std::cell::RefCell::new(Vec::new()));
+
+/// Like `forbid`, but instead of panicking on the first offending
+/// allocation, records every one of them - layout and backtrace - and
+/// returns the full list once `f` returns, so a test can assert "this
+/// region did zero allocations" and get a report of every violation at
+/// once instead of aborting at the first.
+pub fn forbid_recording<F, R>(f: F) -> (R, Vec<Violation>)
+    where
+        F: FnOnce() -> R,
+{
+    VIOLATIONS.with(|v| v.borrow_mut().clear());
+    let result = {
+        let _guard = Guard::new(AllocFlags::FORBID_RECORD);
+        f()
+    };
+    let violations = VIOLATIONS.with(|v| v.borrow_mut().drain(..).collect());
+    (result, violations)
+}
+
+thread_local!(static BUDGET_REMAINING: Cell<Option<u64>> = Cell::new(None));
+
+/// Permits at most `n` allocations within `f`; the `n + 1`th allocation
+/// panics. Implemented the same way as `forbid` - a flag in the
+/// thread-local `AllocFlags` - with a thread-local counter threaded
+/// alongside it that's decremented on every allocation.
+pub fn allow_up_to<F, R>(n: u64, f: F) -> R
+    where
+        F: FnOnce() -> R,
+{
+    let previous = BUDGET_REMAINING.with(|b| b.replace(Some(n)));
+    let result = {
+        let _guard = Guard::new(AllocFlags::BUDGET);
+        f()
+    };
+    BUDGET_REMAINING.with(|b| b.set(previous));
+    result
+}
+
+/// Saves the full prior `AllocFlags` snapshot (not just the bit it adds) so
+/// nested guards restore correctly: a `remove(self.0)`-only `Drop` would
+/// clear bits an *outer* guard had set, e.g. a nested `allow_up_to` inside
+/// a `forbid` clearing `FORBID` when the inner guard drops. Restoring the
+/// full snapshot means each guard's drop undoes exactly what existed right
+/// before it was constructed, regardless of what nested inside it.
 pub struct Guard(AllocFlags);
 impl Guard {
     #[inline(always)]
     fn new(newmode: AllocFlags) -> Self {
         ALLOC_MODE.with(|mode| {
-            let mut new = mode.get();
+            let previous = mode.get();
+            let mut new = previous;
             new.insert(newmode);
             mode.set(new);
 
-            Self(newmode)
+            Self(previous)
         })
     }
 }
@@ -76,41 +317,634 @@ impl Guard {
 impl Drop for Guard {
     #[inline(always)]
     fn drop(&mut self) {
-        ALLOC_MODE.with(|mode| {
-            let mut new = mode.get();
-            new.remove(self.0);
-            mode.set(new);
-        });
+        ALLOC_MODE.with(|mode| mode.set(self.0));
     }
 }
 
 #[inline(always)]
 pub unsafe fn add_log_entry(entry: AllocLog) {
-    ALLOC_LOG.as_ref().unwrap().push(entry).unwrap();
+    alloc_log().push(entry).unwrap();
+}
+
+/// A single still-live allocation, as tracked by `LIVE_ALLOCS`.
+struct LiveAlloc {
+    ptr: usize,
+    layout: Layout,
+    trace: Vec<Option<*mut std::ffi::c_void>>,
+    /// `Some(weight)` if this allocation was sampled into `STATS_MAP` (see
+    /// `sample_decision`), so `dealloc`/`realloc` can apply the same weight
+    /// when backing it out. `None` if it was skipped and never recorded.
+    sample_weight: Option<u64>,
+}
+
+/// Open-addressing table mapping a live allocation's pointer address to its
+/// layout and captured backtrace.
+///
+/// This can't be a plain `HashMap` because inserting into it happens inside
+/// `AllocImpl::alloc`/`dealloc` themselves - any allocation the map's own
+/// bookkeeping performs would recurse straight back into us. So its backing
+/// storage is grown with `std::alloc::System` directly, never through
+/// `AllocImpl`, and it never shrinks.
+struct LiveMap {
+    slots: *mut Option<LiveAlloc>,
+    capacity: usize,
+    len: usize,
+}
+
+unsafe impl Send for LiveMap {}
+unsafe impl Sync for LiveMap {}
+
+impl LiveMap {
+    const fn new() -> Self {
+        Self {
+            slots: std::ptr::null_mut(),
+            capacity: 0,
+            len: 0,
+        }
+    }
+
+    unsafe fn alloc_slots(capacity: usize) -> *mut Option<LiveAlloc> {
+        let layout = Layout::array::<Option<LiveAlloc>>(capacity).unwrap();
+        let raw = std::alloc::System.alloc(layout) as *mut Option<LiveAlloc>;
+        assert!(!raw.is_null(), "insight: bookkeeping allocation failed");
+        for i in 0..capacity {
+            raw.add(i).write(None);
+        }
+        raw
+    }
+
+    unsafe fn grow(&mut self) {
+        let new_capacity = if self.capacity == 0 { 1024 } else { self.capacity * 2 };
+        let new_slots = Self::alloc_slots(new_capacity);
+
+        let old_slots = self.slots;
+        let old_capacity = self.capacity;
+        self.slots = new_slots;
+        self.capacity = new_capacity;
+        self.len = 0;
+
+        if !old_slots.is_null() {
+            for i in 0..old_capacity {
+                if let Some(entry) = (*old_slots.add(i)).take() {
+                    self.insert_raw(entry);
+                }
+            }
+            let layout = Layout::array::<Option<LiveAlloc>>(old_capacity).unwrap();
+            std::alloc::System.dealloc(old_slots as *mut u8, layout);
+        }
+    }
+
+    #[inline]
+    fn index_of(&self, ptr: usize) -> usize {
+        // Fibonacci hashing: multiplying by a golden-ratio-derived odd
+        // constant spreads entropy into the *high* bits of the product, not
+        // the low ones, so for a power-of-two capacity the bucket has to
+        // come from the top `log2(capacity)` bits (shifted down) rather
+        // than `% capacity`, which keeps exactly the low bits a
+        // multiplicative hash mixes worst - that made probe collisions (and
+        // the chained-removal bug they trigger) common instead of rare.
+        let hash = ptr.wrapping_mul(0x9E3779B97F4A7C15);
+        let shift = usize::BITS - self.capacity.trailing_zeros();
+        (hash >> shift) as usize
+    }
+
+    unsafe fn insert_raw(&mut self, entry: LiveAlloc) {
+        let mut idx = self.index_of(entry.ptr);
+        loop {
+            match &*self.slots.add(idx) {
+                None => break,
+                Some(existing) if existing.ptr == entry.ptr => break,
+                _ => idx = (idx + 1) % self.capacity,
+            }
+        }
+        if self.slots.add(idx).read().is_none() {
+            self.len += 1;
+        }
+        self.slots.add(idx).write(Some(entry));
+    }
+
+    unsafe fn insert(
+        &mut self,
+        ptr: usize,
+        layout: Layout,
+        trace: Vec<Option<*mut std::ffi::c_void>>,
+        sample_weight: Option<u64>,
+    ) {
+        if self.capacity == 0 || self.len * 2 >= self.capacity {
+            self.grow();
+        }
+        self.insert_raw(LiveAlloc { ptr, layout, trace, sample_weight });
+    }
+
+    unsafe fn remove(&mut self, ptr: usize) -> Option<LiveAlloc> {
+        if self.slots.is_null() {
+            return None;
+        }
+        let mut idx = self.index_of(ptr);
+        loop {
+            match &*self.slots.add(idx) {
+                None => return None,
+                Some(existing) if existing.ptr == ptr => break,
+                _ => idx = (idx + 1) % self.capacity,
+            }
+        }
+
+        let removed = (*self.slots.add(idx)).take();
+        self.len -= 1;
+
+        // Backward-shift deletion: just clearing `idx` leaves a `None` hole
+        // in the middle of the probe chain, so a later lookup for an entry
+        // that collided with this one (and was therefore placed one or more
+        // slots further along) would stop at the hole and report "not
+        // found" even though the entry is still live one slot over. Slide
+        // the rest of the cluster back to close the hole instead.
+        let mut hole = idx;
+        loop {
+            let next = (hole + 1) % self.capacity;
+            let entry_ptr = match &*self.slots.add(next) {
+                None => break,
+                Some(entry) => entry.ptr,
+            };
+            let ideal = self.index_of(entry_ptr);
+
+            // The entry at `next` must stay put if its ideal bucket falls
+            // after the hole and at or before `next` in probe order (i.e.
+            // moving it back to `hole` would place it before its own probe
+            // start) - otherwise it's safe to slide it back.
+            let must_stay = if hole <= next {
+                ideal > hole && ideal <= next
+            } else {
+                ideal > hole || ideal <= next
+            };
+            if must_stay {
+                break;
+            }
+
+            let moved = (*self.slots.add(next)).take();
+            self.slots.add(hole).write(moved);
+            hole = next;
+        }
+
+        removed
+    }
+
+    unsafe fn for_each(&self, mut f: impl FnMut(&LiveAlloc)) {
+        if self.slots.is_null() {
+            return;
+        }
+        for i in 0..self.capacity {
+            if let Some(entry) = &*self.slots.add(i) {
+                f(entry);
+            }
+        }
+    }
+}
+
+// Live-allocation and call-site-stats tracking are off by default: with
+// `TRACKING_ENABLED == false`, `alloc`/`realloc`/`dealloc` do none of the
+// work that backs `dump_leaks`/`stats`/`write_folded` - no `capture_trace`,
+// no `LiveMap`/`StatsMap` insert - so installing the allocator costs nothing
+// until a caller opts in. This is independent of `LOG_ENABLED`, which gates
+// the separate raw-event queue `dump_alloc` drains.
+static TRACKING_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns on live-allocation + call-site-stats tracking (off by default).
+pub fn enable_tracking() {
+    TRACKING_ENABLED.store(true, SeqCst);
+}
+
+/// Turns off live-allocation + call-site-stats tracking.
+pub fn disable_tracking() {
+    TRACKING_ENABLED.store(false, SeqCst);
+}
+
+static LIVE_ALLOCS: LockedCell<LiveMap> = LockedCell::new(LiveMap::new());
+static LIVE_ALLOCS_LOCK: SpinLock = SpinLock::new();
+
+#[inline(always)]
+unsafe fn with_live_allocs<R>(f: impl FnOnce(&mut LiveMap) -> R) -> R {
+    // `LiveMap`'s own bookkeeping allocations go through `System` directly so
+    // they can't recurse into `AllocImpl::alloc`, but a concurrent allocation
+    // on another thread can genuinely be touching `LIVE_ALLOCS` at the same
+    // time, so this blocks for the other thread rather than bailing - a
+    // test-and-set that gives up here would silently drop the insert/remove
+    // and make a leak detector under-report. Generic over `R` so reads
+    // (`dump_leaks`) take the same lock as writers, not just the writers.
+    // Called from outside the allocator (`dump_leaks`), it also enters
+    // bookkeeping, so the allocations `f` itself makes take the fast path
+    // instead of re-entering this lock on the same thread.
+    let _bookkeeping = enter_bookkeeping();
+    let _guard = LIVE_ALLOCS_LOCK.lock();
+    no_log(|| f(&mut *LIVE_ALLOCS.get()))
+}
+
+// A full `backtrace::trace` on every allocation is what makes `LOG_ENABLED`
+// (and stats collection) too expensive to leave on. `SAMPLE_RATE` lets the
+// common path skip it: only every Nth allocation on a given thread is
+// captured, tracked with a cheap `SAMPLE_COUNTER` instead of anything
+// probabilistic. `SAMPLE_THRESHOLD_BYTES` always captures allocations at or
+// above a given size regardless of the counter, since those are rare and
+// individually significant. Rate 1 (the default) means "log everything",
+// matching the pre-sampling behavior.
+static SAMPLE_RATE: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(1);
+static SAMPLE_THRESHOLD_BYTES: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(usize::MAX);
+
+thread_local!(static SAMPLE_COUNTER: Cell<usize> = Cell::new(0));
+
+/// Sets the sampling rate: 1 captures every allocation (the default), N
+/// captures roughly 1 in every N.
+pub fn set_sample_rate(n: usize) {
+    SAMPLE_RATE.store(n.max(1), SeqCst);
+}
+
+/// Allocations at or above this size are always captured, regardless of the
+/// sample rate.
+pub fn set_sample_size_threshold(bytes: usize) {
+    SAMPLE_THRESHOLD_BYTES.store(bytes, SeqCst);
+}
+
+/// Decides whether this allocation should have its backtrace captured, and
+/// if so, what weight to record it with so that scaled-up totals remain an
+/// unbiased estimate of the true allocation volume. `None` means skip it
+/// entirely - no backtrace, no stats, no log entry.
+fn sample_decision(size: usize) -> Option<u64> {
+    if size >= SAMPLE_THRESHOLD_BYTES.load(SeqCst) {
+        return Some(1);
+    }
+
+    let rate = SAMPLE_RATE.load(SeqCst).max(1);
+    if rate == 1 {
+        return Some(1);
+    }
+
+    SAMPLE_COUNTER.with(|counter| {
+        let next = counter.get() + 1;
+        if next >= rate {
+            counter.set(0);
+            Some(rate as u64)
+        } else {
+            counter.set(next);
+            None
+        }
+    })
+}
+
+fn capture_trace() -> Vec<Option<*mut std::ffi::c_void>> {
+    let mut trace = Vec::new();
+    backtrace::trace(|frame| {
+        let ip = frame.ip();
+        backtrace::resolve(ip, |symbol| {
+            trace.push(symbol.addr());
+        });
+        true
+    });
+    trace
+}
+
+fn resolve_trace(trace: &[Option<*mut std::ffi::c_void>]) -> Vec<String> {
+    let mut resolved = Vec::new();
+    for addr in trace.iter().flatten() {
+        backtrace::resolve(*addr, |symbol| {
+            if let Some(name) = symbol.name() {
+                let s = name.to_string();
+                if !s.contains("backtrace") && !s.contains("GlobalAlloc") && !s.contains("insight") {
+                    resolved.push(s);
+                }
+            }
+        });
+    }
+    resolved
+}
+
+const STATS_HIST_BUCKETS: usize = 64;
+
+/// Hashes the raw frame-address vector captured for an allocation into a
+/// stable key. Used to bucket `STATS_MAP` entries by call site without
+/// resolving symbols (which only happens when a report is requested).
+fn hash_trace(trace: &[Option<*mut std::ffi::c_void>]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for addr in trace {
+        let bits = addr.map(|p| p as usize).unwrap_or(0) as u64;
+        for byte in bits.to_le_bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+#[inline]
+fn size_bucket(size: usize) -> usize {
+    if size == 0 {
+        0
+    } else {
+        (usize::BITS - size.leading_zeros()) as usize
+    }
+}
+
+/// Per-call-site accumulator kept in `STATS_MAP`.
+struct StatsEntry {
+    key: u64,
+    trace: Vec<Option<*mut std::ffi::c_void>>,
+    count: u64,
+    total_bytes: u64,
+    current_bytes: u64,
+    peak_bytes: u64,
+    histogram: [u64; STATS_HIST_BUCKETS],
+}
+
+/// A resolved, reportable view of one `StatsEntry`, as returned by `stats()`.
+#[derive(Clone, Debug)]
+pub struct CallSiteStats {
+    pub frames: Vec<String>,
+    pub count: u64,
+    pub total_bytes: u64,
+    pub peak_bytes: u64,
+    pub histogram: [u64; STATS_HIST_BUCKETS],
+}
+
+/// Same open-addressing/`System`-backed approach as `LiveMap`, keyed by the
+/// hashed call-site instead of the allocation's pointer address.
+struct StatsMap {
+    slots: *mut Option<StatsEntry>,
+    capacity: usize,
+    len: usize,
+}
+
+unsafe impl Send for StatsMap {}
+unsafe impl Sync for StatsMap {}
+
+impl StatsMap {
+    const fn new() -> Self {
+        Self {
+            slots: std::ptr::null_mut(),
+            capacity: 0,
+            len: 0,
+        }
+    }
+
+    unsafe fn alloc_slots(capacity: usize) -> *mut Option<StatsEntry> {
+        let layout = Layout::array::<Option<StatsEntry>>(capacity).unwrap();
+        let raw = std::alloc::System.alloc(layout) as *mut Option<StatsEntry>;
+        assert!(!raw.is_null(), "insight: bookkeeping allocation failed");
+        for i in 0..capacity {
+            raw.add(i).write(None);
+        }
+        raw
+    }
+
+    unsafe fn grow(&mut self) {
+        let new_capacity = if self.capacity == 0 { 256 } else { self.capacity * 2 };
+        let new_slots = Self::alloc_slots(new_capacity);
+
+        let old_slots = self.slots;
+        let old_capacity = self.capacity;
+        self.slots = new_slots;
+        self.capacity = new_capacity;
+        self.len = 0;
+
+        if !old_slots.is_null() {
+            for i in 0..old_capacity {
+                if let Some(entry) = (*old_slots.add(i)).take() {
+                    self.insert_raw(entry);
+                }
+            }
+            let layout = Layout::array::<Option<StatsEntry>>(old_capacity).unwrap();
+            std::alloc::System.dealloc(old_slots as *mut u8, layout);
+        }
+    }
+
+    #[inline]
+    fn index_of(&self, key: u64) -> usize {
+        (key as usize) % self.capacity
+    }
+
+    unsafe fn insert_raw(&mut self, entry: StatsEntry) {
+        let mut idx = self.index_of(entry.key);
+        loop {
+            match &*self.slots.add(idx) {
+                None => break,
+                Some(existing) if existing.key == entry.key => break,
+                _ => idx = (idx + 1) % self.capacity,
+            }
+        }
+        if self.slots.add(idx).read().is_none() {
+            self.len += 1;
+        }
+        self.slots.add(idx).write(Some(entry));
+    }
+
+    unsafe fn entry_mut(&mut self, key: u64, trace: &[Option<*mut std::ffi::c_void>]) -> &mut StatsEntry {
+        if self.capacity == 0 || self.len * 2 >= self.capacity {
+            self.grow();
+        }
+        let mut idx = self.index_of(key);
+        loop {
+            match &*self.slots.add(idx) {
+                None => {
+                    self.insert_raw(StatsEntry {
+                        key,
+                        trace: trace.to_vec(),
+                        count: 0,
+                        total_bytes: 0,
+                        current_bytes: 0,
+                        peak_bytes: 0,
+                        histogram: [0; STATS_HIST_BUCKETS],
+                    });
+                    break;
+                }
+                Some(existing) if existing.key == key => break,
+                _ => idx = (idx + 1) % self.capacity,
+            }
+        }
+        (*self.slots.add(idx)).as_mut().unwrap()
+    }
+
+    unsafe fn find_mut(&mut self, key: u64) -> Option<&mut StatsEntry> {
+        if self.slots.is_null() {
+            return None;
+        }
+        let mut idx = self.index_of(key);
+        loop {
+            match &*self.slots.add(idx) {
+                None => return None,
+                Some(existing) if existing.key == key => return (*self.slots.add(idx)).as_mut(),
+                _ => idx = (idx + 1) % self.capacity,
+            }
+        }
+    }
+
+    /// `weight` scales every recorded quantity so that, under sampling,
+    /// aggregated totals stay an unbiased estimator of the true allocation
+    /// volume: sampling 1-in-N allocations and recording each as if it were
+    /// N allocations keeps the expected total the same as recording all of
+    /// them.
+    unsafe fn record_alloc(&mut self, trace: &[Option<*mut std::ffi::c_void>], size: usize, weight: u64) {
+        let key = hash_trace(trace);
+        let entry = self.entry_mut(key, trace);
+        entry.count += weight;
+        entry.total_bytes += size as u64 * weight;
+        entry.current_bytes += size as u64 * weight;
+        entry.peak_bytes = entry.peak_bytes.max(entry.current_bytes);
+        entry.histogram[size_bucket(size)] += weight;
+    }
+
+    unsafe fn record_dealloc(&mut self, trace: &[Option<*mut std::ffi::c_void>], size: usize, weight: u64) {
+        let key = hash_trace(trace);
+        if let Some(entry) = self.find_mut(key) {
+            entry.current_bytes = entry.current_bytes.saturating_sub(size as u64 * weight);
+        }
+    }
+
+    unsafe fn clear(&mut self) {
+        if !self.slots.is_null() {
+            let layout = Layout::array::<Option<StatsEntry>>(self.capacity).unwrap();
+            std::alloc::System.dealloc(self.slots as *mut u8, layout);
+        }
+        self.slots = std::ptr::null_mut();
+        self.capacity = 0;
+        self.len = 0;
+    }
+
+    unsafe fn for_each(&self, mut f: impl FnMut(&StatsEntry)) {
+        if self.slots.is_null() {
+            return;
+        }
+        for i in 0..self.capacity {
+            if let Some(entry) = &*self.slots.add(i) {
+                f(entry);
+            }
+        }
+    }
+}
+
+static STATS_MAP: LockedCell<StatsMap> = LockedCell::new(StatsMap::new());
+static STATS_LOCK: SpinLock = SpinLock::new();
+
+#[inline(always)]
+unsafe fn with_stats<R>(f: impl FnOnce(&mut StatsMap) -> R) -> R {
+    // See `with_live_allocs`: a concurrent thread can genuinely be touching
+    // `STATS_MAP` at the same time, so block for it rather than dropping the
+    // record on the floor. Generic over `R` so reads (`stats`, `write_folded`,
+    // `reset_stats`) take the same lock as writers, and like
+    // `with_live_allocs` it enters bookkeeping so allocations made by `f`
+    // are neither recorded nor re-enter this lock.
+    let _bookkeeping = enter_bookkeeping();
+    let _guard = STATS_LOCK.lock();
+    no_log(|| f(&mut *STATS_MAP.get()))
+}
+
+/// Returns a per-call-site report - allocation count, total bytes requested,
+/// peak simultaneous bytes, and a size histogram - sorted by total bytes
+/// descending so the hottest allocation sites sort first.
+pub unsafe fn stats() -> Vec<CallSiteStats> {
+    let mut rows = with_stats(|map| {
+        let mut rows = Vec::new();
+        map.for_each(|entry| {
+            rows.push(CallSiteStats {
+                frames: resolve_trace(&entry.trace),
+                count: entry.count,
+                total_bytes: entry.total_bytes,
+                peak_bytes: entry.peak_bytes,
+                histogram: entry.histogram,
+            });
+        });
+        rows
+    });
+    rows.sort_by(|a, b| b.total_bytes.cmp(&a.total_bytes));
+    rows
+}
+
+/// Drops all accumulated call-site statistics, starting a fresh window for
+/// the next `stats()` report.
+pub unsafe fn reset_stats() {
+    with_stats(|map| map.clear());
+}
+
+/// Which quantity becomes the weight column of a folded/collapsed-stack
+/// line written by `write_folded`.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum FoldedWeight {
+    Count,
+    Bytes,
+}
+
+/// Writes every call site in `STATS_MAP` as one collapsed-stack line -
+/// `frame1;frame2;...;frameN <weight>`, outermost caller first and the
+/// allocation site last - the format flamegraph tooling (e.g.
+/// `flamegraph.pl`, `inferno`) consumes directly.
+///
+/// Symbol resolution and string formatting happen inside `with_stats`,
+/// which marks the thread as inside the allocator's own bookkeeping while
+/// it holds its lock, so building the export doesn't itself add entries to
+/// the log/stats it's reporting on.
+pub unsafe fn write_folded<W: std::io::Write>(weight: FoldedWeight, out: &mut W) -> std::io::Result<()> {
+    let rendered = with_stats(|map| {
+        let mut buf = String::new();
+        map.for_each(|entry| {
+            // `resolve_trace` walks the captured addresses innermost-first
+            // (the allocation site first, its callers after); folded-stack
+            // format wants the opposite order.
+            let mut frames = resolve_trace(&entry.trace);
+            frames.reverse();
+
+            let value = match weight {
+                FoldedWeight::Count => entry.count,
+                FoldedWeight::Bytes => entry.total_bytes,
+            };
+
+            buf.push_str(&frames.join(";"));
+            buf.push(' ');
+            buf.push_str(&value.to_string());
+            buf.push('\n');
+        });
+        buf
+    });
+
+    out.write_all(rendered.as_bytes())
+}
+
+/// Convenience wrapper around `write_folded` that (over)writes a file at
+/// `path`.
+pub unsafe fn dump_folded_to_path(weight: FoldedWeight, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+    let mut file = std::fs::File::create(path)?;
+    write_folded(weight, &mut file)
+}
+
+/// Walks everything still tracked in `LIVE_ALLOCS` and prints it grouped by
+/// call site, along with the total live bytes - i.e. a leak report.
+///
+/// Intended to be called at shutdown (or any time you expect the heap to be
+/// quiescent); anything it prints is memory that was never freed.
+pub unsafe fn dump_leaks() {
+    let by_site: std::collections::HashMap<Vec<String>, (usize, usize)> = with_live_allocs(|map| {
+        let mut by_site: std::collections::HashMap<Vec<String>, (usize, usize)> = std::collections::HashMap::new();
+        map.for_each(|entry| {
+            let frames = resolve_trace(&entry.trace);
+            let bucket = by_site.entry(frames).or_insert((0, 0));
+            bucket.0 += 1;
+            bucket.1 += entry.layout.size();
+        });
+        by_site
+    });
+
+    let mut rows: Vec<_> = by_site.into_iter().collect();
+    rows.sort_by(|a, b| b.1 .1.cmp(&a.1 .1));
+
+    for (frames, (count, bytes)) in rows {
+        println!("{} live allocations, {} bytes - {:?}", count, bytes, frames);
+    }
 }
 
 pub unsafe fn dump_alloc() {
     no_log(|| {
-        let log = ALLOC_LOG.as_mut().unwrap();
+        let log = alloc_log();
         while ! log.is_empty() {
             match log.pop() {
                 Ok(ref entry) => {
                     match entry {
                         AllocLog::Test(ref layout, ref bt) => {
-                            let mut trace = Vec::new();
-                            bt.iter().for_each(|addr| {
-                                if addr.is_some() {
-                                    backtrace::resolve(addr.unwrap(), |symbol| {
-                                        if symbol.name().is_some() {
-                                            let s = symbol.name().unwrap().to_string();
-                                            if ! s.contains("backtrace") && ! s.contains("GlobalAlloc") && ! s.contains("insight"){
-                                                trace.push(s);
-                                            }
-                                        }
-                                    });
-                                }
-                            });
-                            println!("{:?} - {:?}", layout, trace);
+                            println!("{:?} - {:?}", layout, resolve_trace(bt));
                         },
                         _ => {}
                     }
@@ -127,13 +961,13 @@ unsafe impl<A> GlobalAlloc for AllocImpl<A>
 {
     #[inline(always)]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        if ALLOC_LOG.is_none() && ! ALLOC_INITIALIZING.load(SeqCst) {
-            ALLOC_INITIALIZING.store(true, SeqCst);
-            ALLOC_LOG = Some(ArrayQueue::new(ALLOC_LOG_SIZE));
-            //ALLOC_MODE.with(|mode| {
-            //    mode.set(AllocFlags::LOG_ENABLED);
-            //});
-        }
+        let _bookkeeping = match enter_bookkeeping() {
+            Some(guard) => guard,
+            None => return self.inner.alloc(layout),
+        };
+
+        let tracking = TRACKING_ENABLED.load(SeqCst);
+        let weight = sample_decision(layout.size());
 
         ALLOC_MODE.with(|mode| {
             no_log(|| {
@@ -142,33 +976,99 @@ unsafe impl<A> GlobalAlloc for AllocImpl<A>
                     panic!("Allocation performed when forbidden")
                 }
 
-                if mode.contains(AllocFlags::LOG_ENABLED) {
-
-                    let mut trace = Vec::new();
-
-                    backtrace::trace(|frame| {
-                        let ip = frame.ip();
-                        backtrace::resolve(ip, |symbol| {
-                            trace.push(symbol.addr());
+                if mode.contains(AllocFlags::FORBID_RECORD) {
+                    let trace = capture_trace();
+                    VIOLATIONS.with(|v| {
+                        v.borrow_mut().push(Violation {
+                            layout,
+                            frames: resolve_trace(&trace),
                         });
-                        true
                     });
+                }
 
+                if mode.contains(AllocFlags::BUDGET) {
+                    let exceeded = BUDGET_REMAINING.with(|b| match b.get() {
+                        Some(0) => true,
+                        Some(remaining) => {
+                            b.set(Some(remaining - 1));
+                            false
+                        }
+                        None => false,
+                    });
+                    if exceeded {
+                        panic!("Allocation budget exceeded");
+                    }
+                }
+
+                if mode.contains(AllocFlags::LOG_ENABLED) && weight.is_some() {
+                    let trace = capture_trace();
                     add_log_entry(AllocLog::Test(layout, trace));
                 }
             });
         });
 
-        self.inner.alloc(layout)
+        let ptr = self.inner.alloc(layout);
+        if tracking && !ptr.is_null() {
+            let trace = match weight {
+                Some(w) => {
+                    let trace = capture_trace();
+                    with_stats(|map| map.record_alloc(&trace, layout.size(), w));
+                    trace
+                }
+                None => Vec::new(),
+            };
+            with_live_allocs(|map| map.insert(ptr as usize, layout, trace, weight));
+        }
+
+        ptr
     }
 
     #[inline(always)]
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-        self.inner.realloc(ptr, layout, new_size)
+        let _bookkeeping = match enter_bookkeeping() {
+            Some(guard) => guard,
+            None => return self.inner.realloc(ptr, layout, new_size),
+        };
+
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if TRACKING_ENABLED.load(SeqCst) && !new_ptr.is_null() {
+            with_live_allocs(|map| {
+                if let Some(old) = map.remove(ptr as usize) {
+                    if let Some(w) = old.sample_weight {
+                        with_stats(|stats| stats.record_dealloc(&old.trace, old.layout.size(), w));
+                    }
+                }
+
+                let new_layout = Layout::from_size_align(new_size, layout.align()).unwrap_or(layout);
+                let weight = sample_decision(new_layout.size());
+                let trace = match weight {
+                    Some(w) => {
+                        let trace = capture_trace();
+                        with_stats(|stats| stats.record_alloc(&trace, new_layout.size(), w));
+                        trace
+                    }
+                    None => Vec::new(),
+                };
+                map.insert(new_ptr as usize, new_layout, trace, weight);
+            });
+        }
+
+        new_ptr
     }
 
     #[inline(always)]
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(_bookkeeping) = enter_bookkeeping() {
+            if TRACKING_ENABLED.load(SeqCst) {
+                with_live_allocs(|map| {
+                    if let Some(old) = map.remove(ptr as usize) {
+                        if let Some(w) = old.sample_weight {
+                            with_stats(|stats| stats.record_dealloc(&old.trace, old.layout.size(), w));
+                        }
+                    }
+                });
+            }
+        }
         self.inner.dealloc(ptr, layout)
     }
 }
@@ -193,10 +1093,135 @@ fn create_logger() -> slog::Logger {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // `AllocImpl`'s FORBID/BUDGET/LOG_ENABLED/tracking paths only fire when
+    // it's actually installed as the process's global allocator, so the
+    // test binary installs it here. `ALLOC_MODE`/`TRACKING_ENABLED`/
+    // `STATS_MAP` are process-wide state, so tests that touch them take
+    // `STATE_LOCK` to avoid racing each other under `cargo test`'s default
+    // parallelism. The budget test panics while holding it, so the lock is
+    // taken through its poison rather than unwrapped.
+    #[global_allocator]
+    static TEST_ALLOC: Allocator = Allocator;
+
+    static STATE_LOCK: Mutex<()> = Mutex::new(());
+
     #[test]
     fn tracker_macro() {
         println!("Test 1");
     }
+
+    #[test]
+    fn forbid_recording_reports_the_violating_allocation() {
+        let _state = STATE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (_, violations) = forbid_recording(|| {
+            let v: Vec<u8> = Vec::with_capacity(64);
+            drop(v);
+        });
+        assert!(!violations.is_empty(), "expected the Vec allocation to be recorded");
+        assert!(violations.iter().any(|v| v.layout.size() >= 64));
+    }
+
+    #[test]
+    #[should_panic(expected = "Allocation budget exceeded")]
+    fn allow_up_to_panics_on_the_n_plus_first_allocation() {
+        let _state = STATE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        allow_up_to(1, || {
+            let _a: Vec<u8> = Vec::with_capacity(8);
+            let _b: Vec<u8> = Vec::with_capacity(8);
+        });
+    }
+
+    #[test]
+    fn write_folded_emits_one_numeric_weighted_line_per_call_site() {
+        let _state = STATE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        unsafe {
+            reset_stats();
+        }
+        enable_tracking();
+        with_logging(|| {
+            let _v: Vec<u8> = Vec::with_capacity(96);
+        });
+        disable_tracking();
+
+        let mut out = Vec::new();
+        unsafe {
+            write_folded(FoldedWeight::Bytes, &mut out).unwrap();
+        }
+        let text = String::from_utf8(out).unwrap();
+        assert!(!text.is_empty(), "expected at least one folded-stack line");
+        for line in text.lines() {
+            let weight = line.rsplit(' ').next().unwrap();
+            assert!(weight.parse::<u64>().is_ok(), "line {:?} missing a numeric weight", line);
+        }
+    }
+
+    // Regresses the `LiveMap::remove` probe-chain bug: removing an entry
+    // that an earlier insert collided with used to leave a `None` hole in
+    // the middle of the cluster, stranding every entry probed past it -
+    // `remove` would report them as already gone, `record_dealloc` would
+    // never fire for them, and `dump_leaks` would report them as permanent
+    // phantom leaks. Build a real colliding cluster (same home bucket),
+    // remove from the middle, then confirm every other member is still
+    // reachable and the map ends up empty.
+    #[test]
+    fn live_map_remove_repairs_probe_chain() {
+        unsafe {
+            let mut map = LiveMap::new();
+            map.insert(1, Layout::new::<u8>(), Vec::new(), None);
+            let home = map.index_of(1);
+
+            let mut ptrs = vec![1usize];
+            let mut candidate = 2usize;
+            while ptrs.len() < 6 {
+                if map.index_of(candidate) == home {
+                    map.insert(candidate, Layout::new::<u8>(), Vec::new(), None);
+                    ptrs.push(candidate);
+                }
+                candidate += 1;
+            }
+            assert_eq!(map.len, ptrs.len());
+
+            // Remove from the middle of the cluster, not the head or tail -
+            // that's what used to leave survivors unreachable.
+            let removed = ptrs.remove(ptrs.len() / 2);
+            assert!(map.remove(removed).is_some());
+
+            for ptr in &ptrs {
+                assert!(
+                    map.remove(*ptr).is_some(),
+                    "ptr {} was stranded after an earlier removal broke its probe chain",
+                    ptr
+                );
+            }
+
+            assert_eq!(map.len, 0);
+        }
+    }
+}
+
+// Models the first-allocation race on `alloc_log_state::PTR`/`INITIALIZING`
+// under loom's exhaustive thread-interleaving exploration. Run with
+// `RUSTFLAGS="--cfg loom" cargo test --release --lib -- alloc_log`.
+#[cfg(loom)]
+mod loom_tests {
+    use super::alloc_log;
+    use loom::thread;
+
+    #[test]
+    fn alloc_log_initializes_exactly_once() {
+        loom::model(|| {
+            let t1 = thread::spawn(|| alloc_log() as *const _);
+            let t2 = thread::spawn(|| alloc_log() as *const _);
+
+            let a = t1.join().unwrap();
+            let b = t2.join().unwrap();
+
+            assert_eq!(a, b, "racing threads must observe the same initialized queue");
+        });
+    }
 }
 
 